@@ -1,50 +1,145 @@
 use std::{
-    collections::HashMap,
-    io::Write,
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
     thread::{self, JoinHandle},
 };
 
 use itertools::Itertools;
+use jpeg_encoder::{ColorType, Encoder as JpegEncoderImpl};
 use kamera::Camera;
+use mio::{
+    net::{TcpListener as MioTcpListener, TcpStream as MioTcpStream},
+    Events, Interest, Poll, Token, Waker,
+};
+use openh264::{encoder::Encoder as H264EncoderImpl, formats::YUVBuffer};
+
+/// Magic prefix on every binary frame header so clients can resync on the
+/// start of a frame. Spells `GSCR` (green-screen).
+const FRAME_MAGIC: u32 = 0x4753_4352;
+
+/// Poll token for the listening socket.
+const LISTENER: Token = Token(0);
+/// Poll token for the channel waker the camera thread uses to hand over frames.
+const WAKER: Token = Token(1);
+/// Default per-client outbound queue depth at which a slow client starts
+/// shedding frames (see [`Client::enqueue_frame`]).
+const HIGH_WATER_MARK: usize = 8;
+/// A keyframe is forced on every client at least this often so late joiners and
+/// clients that missed a delta resynchronise without an explicit request.
+const KEYFRAME_INTERVAL: u64 = 60;
+
+/// Frame-header packet kinds. A KEYFRAME carries the full encoded buffer; a
+/// DELTA carries run-length differences against the client's last buffer.
+const KIND_KEYFRAME: u8 = 0;
+const KIND_DELTA: u8 = 1;
 
 fn main() {
     let state = Arc::new(Mutex::new(State::default()));
-    let _ = start_tcp_listener(state.clone());
-    start_camera(state);
+    let keyframe_requested = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel::<EncodedFrame>();
+    let (_io, waker) = start_io_loop(rx, HIGH_WATER_MARK, keyframe_requested.clone());
+    let _ = start_control_listener(state.clone(), keyframe_requested);
+    start_camera(state, select_encoder(), tx, waker);
+}
+
+/// Pick the encoder at startup from the first CLI argument, falling back to the
+/// `GREEN_SCREEN_CODEC` environment variable and finally to MJPEG. This is the
+/// single place the codec is chosen, so the `codec_id` written into every frame
+/// header stays in step with what is actually emitted.
+fn select_encoder() -> Box<dyn Encoder> {
+    let codec = std::env::args()
+        .nth(1)
+        .or_else(|| std::env::var("GREEN_SCREEN_CODEC").ok())
+        .unwrap_or_else(|| "jpeg".to_string());
+
+    return match codec.as_str() {
+        "h264" => Box::new(H264Encoder::new()),
+        "raw" => Box::new(RawEncoder),
+        _ => Box::new(JpegEncoder::new(90)),
+    };
+}
+
+/// One frame handed from the capture thread to the I/O loop, which then derives
+/// a per-client keyframe or delta packet from it. `bytes` is the on-wire
+/// keyframe body; `raw` is the pre-encode RGBA buffer that deltas are diffed
+/// against. Deltas are only emitted when `supports_delta` is set, i.e. when
+/// `bytes` is itself the raw buffer so a decoded keyframe and the delta
+/// reference share one lossless representation; lossy or inter-frame codecs
+/// (JPEG, H.264) clear it and send each frame's whole encoded output instead
+/// (for H.264 that output can reference earlier frames).
+struct EncodedFrame {
+    codec_id: u8,
+    w: u32,
+    h: u32,
+    bytes: Vec<u8>,
+    raw: Vec<u8>,
+    supports_delta: bool,
 }
 
 struct State {
     filter: FilterType,
-    con_count: usize,
-    streams: HashMap<usize, TcpStream>,
+    thresholds: Thresholds,
+    back_ground_frame: Vec<Color>,
+    recapture: bool,
+}
+
+/// Keying constants consumed by [`FilterType::key_alpha`] and
+/// [`FilterType::suppress_spill`]. Held on [`State`] so
+/// an operator can retune them at runtime over the control channel without a
+/// rebuild. Distances are measured in the Cb/Cr chroma plane.
+#[derive(Clone, Copy)]
+struct Thresholds {
+    /// Chroma distance below which a pixel is fully keyed out (alpha 0).
+    inner_radius: f32,
+    /// Chroma distance above which a pixel is fully kept (alpha 255).
+    outer_radius: f32,
+    /// How hard to pull a dominant key channel toward the other two when
+    /// suppressing spill; `0.0` disables, `1.0` clamps to their average.
+    spill: f32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        return Self {
+            inner_radius: 20.0,
+            outer_radius: 70.0,
+            spill: 1.0,
+        };
+    }
 }
 
 impl State {
-    fn process_payload(&mut self, payload: String) {
-        self.streams
-            .iter_mut()
-            .filter_map(|(key, stream)| {
-                let w = writeln!(stream, "{}", payload);
-                let r = stream.flush();
-
-                return if r.is_err() || w.is_err() {
-                    Some(key.clone())
-                } else {
-                    None
-                };
-            })
-            .collect::<Vec<usize>>()
-            .into_iter()
-            .for_each(|key| {
-                self.streams.remove_entry(&key);
-            });
+    /// Apply an operator command received over the control channel.
+    fn apply_command(&mut self, command: Command) {
+        match command {
+            Command::SetFilter(filter) => self.filter = filter,
+            Command::SetThresholds(thresholds) => self.thresholds = thresholds,
+            Command::RecaptureBackground => self.recapture = true,
+            // Handled in the control listener against the I/O loop's flag.
+            Command::RequestKeyframe => {}
+        }
     }
 
-    fn insert_stream(&mut self, stream: TcpStream) {
-        self.con_count += 1;
-        self.streams.insert(self.con_count, stream);
+    /// Composite the current frame against the captured plate with the active
+    /// filter. The keying thresholds and background plate are threaded through
+    /// `self` so the per-pixel keying stays the single compositing step without
+    /// widening its signature.
+    fn apply_filter(&self, current_frame: Vec<Color>) -> Vec<Color> {
+        return current_frame
+            .into_iter()
+            .enumerate()
+            .map(|(index, color)| {
+                let alpha = self.filter.key_alpha(&color, &self.thresholds);
+                let foreground = self.filter.suppress_spill(color, alpha, self.thresholds.spill);
+                return composite(&foreground, &self.back_ground_frame[index], alpha);
+            })
+            .collect::<Vec<Color>>();
     }
 }
 
@@ -52,34 +147,321 @@ impl Default for State {
     fn default() -> Self {
         Self {
             filter: FilterType::Red,
-            con_count: Default::default(),
-            streams: Default::default(),
+            thresholds: Thresholds::default(),
+            back_ground_frame: Default::default(),
+            recapture: false,
         }
     }
 }
 
-fn start_tcp_listener(state: Arc<Mutex<State>>) -> JoinHandle<()> {
-    let handle = thread::spawn(move || {
-        let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
-        println!("Listening at 127.0.0.1:8080");
+/// One connected viewer. Finished frames are pushed onto `queue`; at most one
+/// buffer is ever mid-flight so the wire stays frame-aligned even when a write
+/// blocks partway through.
+struct Client {
+    stream: MioTcpStream,
+    in_flight: Option<(Vec<u8>, usize)>,
+    queue: VecDeque<Vec<u8>>,
+    /// Raw RGBA buffer of the last packet queued for this client, i.e. the
+    /// reference a delta is computed against. `None` until it has been queued a
+    /// keyframe, always `None` for codecs without delta support, and reset to
+    /// `None` whenever its backlog is dropped so it is never sent a delta
+    /// against a frame it did not receive.
+    last_sent: Option<Vec<u8>>,
+}
+
+impl Client {
+    fn new(stream: MioTcpStream) -> Self {
+        return Self {
+            stream,
+            in_flight: None,
+            queue: VecDeque::new(),
+            last_sent: None,
+        };
+    }
+
+    /// Build the packet this client should receive for `frame`, choosing a
+    /// keyframe when its reference is unknown, when one is forced, or on the
+    /// periodic interval, and a delta otherwise. Updates the client's reference
+    /// so the invariant "no delta without a known reference" always holds.
+    fn packet_for(
+        &mut self,
+        frame: &EncodedFrame,
+        force_keyframe: bool,
+        counter: u64,
+    ) -> Vec<u8> {
+        let keyframe =
+            force_keyframe || self.last_sent.is_none() || counter % KEYFRAME_INTERVAL == 0;
+
+        let packet = match &self.last_sent {
+            Some(last) if frame.supports_delta && !keyframe => {
+                let body = encode_delta(last, &frame.raw);
+                build_tcp_payload(KIND_DELTA, frame.codec_id, frame.w, frame.h, body)
+            }
+            _ => build_tcp_payload(KIND_KEYFRAME, frame.codec_id, frame.w, frame.h, frame.bytes.clone()),
+        };
+
+        // Only codecs whose keyframe is the raw buffer can be deltated against;
+        // for the rest every packet is a KEYFRAME carrying the codec's whole
+        // output for the frame and no reference is kept.
+        self.last_sent = frame.supports_delta.then(|| frame.raw.clone());
+        return packet;
+    }
+
+    /// Build this client's packet for `frame` and enqueue it. Newest-frame-wins:
+    /// once the queue is at the high-water mark a slow client sheds frames rather
+    /// than backpressuring the producer. For codecs without delta support the
+    /// oldest queued frame is simply dropped; delta streams can't
+    /// shed a single frame without orphaning every delta queued behind it, so the
+    /// whole backlog is flushed and the client resynchronised with a fresh
+    /// keyframe instead.
+    fn enqueue_frame(
+        &mut self,
+        frame: &EncodedFrame,
+        force_keyframe: bool,
+        counter: u64,
+        high_water: usize,
+    ) {
+        if self.queue.len() >= high_water {
+            if frame.supports_delta {
+                self.queue.clear();
+                self.last_sent = None;
+            } else {
+                self.queue.pop_front();
+            }
+        }
+        let packet = self.packet_for(frame, force_keyframe, counter);
+        self.queue.push_back(packet);
+    }
+
+    fn has_pending(&self) -> bool {
+        return self.in_flight.is_some() || !self.queue.is_empty();
+    }
 
+    /// Drain as much queued data as the socket will accept without blocking.
+    /// Returns `Err` once the connection is closed or errors for good.
+    fn write_ready(&mut self) -> io::Result<()> {
         loop {
-            for stream in listener.incoming() {
-                if stream.is_err() {
-                    continue;
+            if self.in_flight.is_none() {
+                match self.queue.pop_front() {
+                    Some(buf) => self.in_flight = Some((buf, 0)),
+                    None => return Ok(()),
                 }
+            }
 
-                let stream = stream.unwrap();
-                let mut state = state.lock().unwrap();
-                state.insert_stream(stream);
+            let (buf, offset) = self.in_flight.as_mut().unwrap();
+            match self.stream.write(&buf[*offset..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    *offset += n;
+                    if *offset >= buf.len() {
+                        self.in_flight = None;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
             }
         }
+    }
+}
+
+/// Spawn the readiness-based I/O loop. Returns its handle and a [`Waker`] the
+/// camera thread pings after pushing a frame onto `rx`.
+fn start_io_loop(
+    rx: Receiver<EncodedFrame>,
+    high_water: usize,
+    keyframe_requested: Arc<AtomicBool>,
+) -> (JoinHandle<()>, Arc<Waker>) {
+    let poll = Poll::new().unwrap();
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER).unwrap());
+
+    let handle = thread::spawn(move || {
+        run_io_loop(poll, rx, high_water, keyframe_requested);
+    });
+
+    return (handle, waker);
+}
+
+fn run_io_loop(
+    mut poll: Poll,
+    rx: Receiver<EncodedFrame>,
+    high_water: usize,
+    keyframe_requested: Arc<AtomicBool>,
+) {
+    let mut listener = MioTcpListener::bind("127.0.0.1:8080".parse().unwrap()).unwrap();
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)
+        .unwrap();
+    println!("Listening at 127.0.0.1:8080");
+
+    let mut events = Events::with_capacity(128);
+    let mut clients: HashMap<Token, Client> = HashMap::new();
+    let mut next_token = 2usize;
+    let mut frame_counter = 0u64;
+
+    loop {
+        poll.poll(&mut events, None).unwrap();
+
+        let mut closed: Vec<Token> = Vec::new();
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => accept_connections(&mut listener, poll.registry(), &mut clients, &mut next_token),
+                WAKER => {
+                    while let Ok(frame) = rx.try_recv() {
+                        frame_counter += 1;
+                        let force = keyframe_requested.swap(false, Ordering::Relaxed);
+                        for client in clients.values_mut() {
+                            client.enqueue_frame(&frame, force, frame_counter, high_water);
+                        }
+                    }
+                    for (token, client) in clients.iter_mut() {
+                        if client.write_ready().is_err() {
+                            closed.push(*token);
+                        }
+                    }
+                    update_interests(poll.registry(), &mut clients);
+                }
+                token => {
+                    if let Some(client) = clients.get_mut(&token) {
+                        if event.is_writable() && client.write_ready().is_err() {
+                            closed.push(token);
+                        }
+                        if event.is_read_closed() || event.is_error() {
+                            closed.push(token);
+                        }
+                    }
+                    update_interests(poll.registry(), &mut clients);
+                }
+            }
+        }
+
+        for token in closed {
+            if let Some(mut client) = clients.remove(&token) {
+                let _ = poll.registry().deregister(&mut client.stream);
+            }
+        }
+    }
+}
+
+/// Accept every pending connection, registering each as a new pollable source.
+fn accept_connections(
+    listener: &mut MioTcpListener,
+    registry: &mio::Registry,
+    clients: &mut HashMap<Token, Client>,
+    next_token: &mut usize,
+) {
+    loop {
+        match listener.accept() {
+            Ok((mut stream, _addr)) => {
+                let token = Token(*next_token);
+                *next_token += 1;
+                registry
+                    .register(&mut stream, token, Interest::READABLE)
+                    .unwrap();
+                clients.insert(token, Client::new(stream));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(_) => return,
+        }
+    }
+}
+
+/// Subscribe to writable readiness only while a client still has data queued so
+/// we don't spin on a permanently-writable idle socket.
+fn update_interests(registry: &mio::Registry, clients: &mut HashMap<Token, Client>) {
+    for (token, client) in clients.iter_mut() {
+        let interest = if client.has_pending() {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+        let _ = registry.reregister(&mut client.stream, *token, interest);
+    }
+}
+
+/// Operator commands parsed off the control channel, mirroring the discrete
+/// typed-command style of the ATEM connection protocol.
+enum Command {
+    SetFilter(FilterType),
+    SetThresholds(Thresholds),
+    RecaptureBackground,
+    RequestKeyframe,
+}
+
+impl Command {
+    /// Parse one whitespace-separated control line. Returns `None` on anything
+    /// malformed so the listener can skip it and keep the connection alive.
+    fn parse(line: &str) -> Option<Command> {
+        let mut parts = line.split_whitespace();
+
+        return match parts.next()? {
+            "SetFilter" => Some(Command::SetFilter(FilterType::parse(parts.next()?)?)),
+            "SetThresholds" => Some(Command::SetThresholds(Thresholds {
+                inner_radius: parts.next()?.parse().ok()?,
+                outer_radius: parts.next()?.parse().ok()?,
+                spill: parts.next()?.parse().ok()?,
+            })),
+            "RecaptureBackground" => Some(Command::RecaptureBackground),
+            "RequestKeyframe" => Some(Command::RequestKeyframe),
+            _ => None,
+        };
+    }
+}
+
+fn start_control_listener(
+    state: Arc<Mutex<State>>,
+    keyframe_requested: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    let handle = thread::spawn(move || {
+        let listener = TcpListener::bind("127.0.0.1:8081").unwrap();
+        println!("Control channel at 127.0.0.1:8081");
+
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+
+            // One thread per connection so several operators can be connected at
+            // once and a client that connects but never sends can't wedge the
+            // whole control channel.
+            let state = state.clone();
+            let keyframe_requested = keyframe_requested.clone();
+            thread::spawn(move || serve_control_connection(stream, state, keyframe_requested));
+        }
     });
 
     return handle;
 }
 
-fn start_camera(state: Arc<Mutex<State>>) {
+/// Read and apply control commands line-by-line for a single connection until it
+/// closes or errors.
+fn serve_control_connection(
+    stream: std::net::TcpStream,
+    state: Arc<Mutex<State>>,
+    keyframe_requested: Arc<AtomicBool>,
+) {
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+
+        match Command::parse(&line) {
+            // A keyframe request targets the I/O loop, not capture state, so it
+            // is flipped on a flag the loop drains per frame.
+            Some(Command::RequestKeyframe) => keyframe_requested.store(true, Ordering::Relaxed),
+            Some(command) => state.lock().unwrap().apply_command(command),
+            None => {}
+        }
+    }
+}
+
+fn start_camera(
+    state: Arc<Mutex<State>>,
+    mut encoder: Box<dyn Encoder>,
+    tx: Sender<EncodedFrame>,
+    waker: Arc<Waker>,
+) {
     let camera = Camera::new_default_device();
     camera.start();
 
@@ -88,31 +470,182 @@ fn start_camera(state: Arc<Mutex<State>>) {
     };
 
     let (w, h) = frame.size_u32();
-    let back_ground_frame = frame.data().data_u8().to_colors();
+    state.lock().unwrap().back_ground_frame = frame.data().data_u8().to_colors();
 
     loop {
         let Some(frame) = camera.wait_for_frame() else {
             return;
         };
 
-        let mut state = state.lock().unwrap();
         let current_frame = frame.data().data_u8().to_colors();
-        let modified_frame = state.filter.apply_to(&back_ground_frame, current_frame);
-        let payload = build_tcp_payload(w, h, modified_frame.to_buffer());
-        state.process_payload(payload);
+
+        // Hold the lock only for keying; encoding and hand-off happen unlocked
+        // so a slow consumer can never stall capture.
+        let modified_frame = {
+            let mut state = state.lock().unwrap();
+
+            if state.recapture {
+                state.back_ground_frame = current_frame.clone();
+                state.recapture = false;
+            }
+
+            state.apply_filter(current_frame)
+        };
+
+        let encoded = encoder.encode(w, h, &modified_frame);
+        let _ = tx.send(EncodedFrame {
+            codec_id: encoder.codec_id(),
+            w,
+            h,
+            bytes: encoded,
+            raw: modified_frame.to_buffer(),
+            supports_delta: encoder.supports_delta(),
+        });
+        let _ = waker.wake();
+    }
+}
+
+/// Pack one packet behind a fixed binary header so the stream is
+/// self-delimiting: `magic, kind, codec_id, width, height, payload_len`
+/// followed by `payload_len` body bytes. All integers are big-endian. The body
+/// is a full encoded buffer for a KEYFRAME or delta segments for a DELTA.
+fn build_tcp_payload(kind: u8, codec_id: u8, w: u32, h: u32, body: Vec<u8>) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(18 + body.len());
+    payload.extend_from_slice(&FRAME_MAGIC.to_be_bytes());
+    payload.push(kind);
+    payload.push(codec_id);
+    payload.extend_from_slice(&w.to_be_bytes());
+    payload.extend_from_slice(&h.to_be_bytes());
+    payload.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    payload.extend_from_slice(&body);
+
+    return payload;
+}
+
+/// Run-length diff `new` against `old`, producing a delta body: the new buffer
+/// length followed by `(offset, run_len, bytes...)` segments covering every
+/// stretch where `new` differs from `old`. Unchanged stretches are omitted, so
+/// a client holding `old` can reconstruct `new` exactly.
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(new.len() as u32).to_be_bytes());
+
+    let mut i = 0;
+    while i < new.len() {
+        if i < old.len() && old[i] == new[i] {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let mut run: Vec<u8> = Vec::new();
+        while i < new.len() && !(i < old.len() && old[i] == new[i]) {
+            run.push(new[i]);
+            i += 1;
+        }
+
+        body.extend_from_slice(&(start as u32).to_be_bytes());
+        body.extend_from_slice(&(run.len() as u32).to_be_bytes());
+        body.extend_from_slice(&run);
+    }
+
+    return body;
+}
+
+/// Stage between [`State::apply_filter`] and the I/O loop that turns a
+/// composited frame into a compact on-wire representation. Swapping the
+/// implementation at startup changes the codec without touching the capture or
+/// networking code.
+trait Encoder {
+    /// Encode one `w`×`h` RGBA frame into a codec-specific byte buffer.
+    fn encode(&mut self, w: u32, h: u32, frame: &[Color]) -> Vec<u8>;
+
+    /// Identifier written into the frame header so clients know how to decode.
+    fn codec_id(&self) -> u8;
+
+    /// Whether this codec's keyframe body is the raw RGBA buffer, so the
+    /// per-client delta path can diff later frames against it losslessly. Lossy
+    /// or inter-frame codecs leave this `false` and send every frame's encoded
+    /// output whole, never as a delta.
+    fn supports_delta(&self) -> bool {
+        return false;
+    }
+}
+
+/// Identity encoder: emits the raw RGBA buffer untouched. Bandwidth-heavy by
+/// itself, but the only codec whose keyframe and delta reference share one
+/// lossless representation, so it is the codec the inter-frame delta path runs
+/// on.
+struct RawEncoder;
+
+impl Encoder for RawEncoder {
+    fn encode(&mut self, _w: u32, _h: u32, frame: &[Color]) -> Vec<u8> {
+        return frame.to_buffer();
+    }
+
+    fn codec_id(&self) -> u8 {
+        return 0;
+    }
+
+    fn supports_delta(&self) -> bool {
+        return true;
+    }
+}
+
+/// JPEG-per-frame (MJPEG) encoder. Simple, stateless, and decodable by any
+/// image library.
+struct JpegEncoder {
+    quality: u8,
+}
+
+impl JpegEncoder {
+    fn new(quality: u8) -> Self {
+        return Self { quality };
+    }
+}
+
+impl Encoder for JpegEncoder {
+    fn encode(&mut self, w: u32, h: u32, frame: &[Color]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let encoder = JpegEncoderImpl::new(&mut out, self.quality);
+        let _ = encoder.encode(&frame.to_buffer(), w as u16, h as u16, ColorType::Rgba);
+
+        return out;
+    }
+
+    fn codec_id(&self) -> u8 {
+        return 1;
     }
 }
 
-fn build_tcp_payload(w: u32, h: u32, frame_buffer: Vec<u8>) -> String {
-    let mut json = String::new();
-    json.push('{');
-    json.push_str(format!("\"width\": {w},").as_str());
-    json.push_str(format!("\"height\": {h},").as_str());
-    json.push_str("\"encoding-order\": \"RGBA\",");
-    json.push_str(format!("\"image\": {:?}", frame_buffer).as_str());
-    json.push('}');
+/// H.264 elementary-stream encoder. Emits an Annex-B NAL sequence per frame so
+/// clients can feed it straight into a standard decoder.
+struct H264Encoder {
+    inner: H264EncoderImpl,
+}
 
-    return json;
+impl H264Encoder {
+    fn new() -> Self {
+        return Self {
+            inner: H264EncoderImpl::new().expect("failed to initialise H.264 encoder"),
+        };
+    }
+}
+
+impl Encoder for H264Encoder {
+    fn encode(&mut self, w: u32, h: u32, frame: &[Color]) -> Vec<u8> {
+        let yuv = YUVBuffer::with_rgb(w as usize, h as usize, &frame.to_rgb());
+        let bitstream = self
+            .inner
+            .encode(&yuv)
+            .expect("failed to encode H.264 frame");
+
+        return bitstream.to_vec();
+    }
+
+    fn codec_id(&self) -> u8 {
+        return 2;
+    }
 }
 
 #[derive(Clone)]
@@ -142,6 +675,7 @@ impl From<Color> for [u8; 4] {
 
 trait ColorsToBuffer {
     fn to_buffer(&self) -> Vec<u8>;
+    fn to_rgb(&self) -> Vec<u8>;
 }
 
 impl ColorsToBuffer for [Color] {
@@ -152,6 +686,13 @@ impl ColorsToBuffer for [Color] {
             .flatten()
             .collect::<Vec<u8>>();
     }
+
+    fn to_rgb(&self) -> Vec<u8> {
+        return self
+            .into_iter()
+            .flat_map(|color| [color.r, color.g, color.b])
+            .collect::<Vec<u8>>();
+    }
 }
 
 trait BufferToColor {
@@ -168,6 +709,7 @@ impl BufferToColor for [u8] {
     }
 }
 
+#[derive(Clone, Copy)]
 enum FilterType {
     Red,
     Blue,
@@ -175,54 +717,131 @@ enum FilterType {
 }
 
 impl FilterType {
-    fn apply_to(&self, back_ground_frame: &Vec<Color>, current_frame: Vec<Color>) -> Vec<Color> {
-        return current_frame
-            .into_iter()
-            .enumerate()
-            .map(|(index, color)| {
-                return if self.should_cut_off(&color) {
-                    back_ground_frame[index].clone()
-                } else {
-                    color
-                };
-            })
-            .collect::<Vec<Color>>();
+    fn parse(s: &str) -> Option<FilterType> {
+        return match s {
+            "Red" => Some(FilterType::Red),
+            "Blue" => Some(FilterType::Blue),
+            "Green" => Some(FilterType::Green),
+            _ => None,
+        };
     }
 
-    fn should_cut_off(&self, color: &Color) -> bool {
-        let cut_off_range = 150..255;
-        let cut_off_1 = 20;
-        let cut_off_2 = 20;
-        let cut_off_variance = 120;
+    /// Reference chroma (Cb, Cr) of the pure key colour this filter targets.
+    fn key_chroma(&self) -> (f32, f32) {
+        let (r, g, b) = match self {
+            FilterType::Red => (255.0, 0.0, 0.0),
+            FilterType::Blue => (0.0, 0.0, 255.0),
+            FilterType::Green => (0.0, 255.0, 0.0),
+        };
+        let (_, cb, cr) = rgb_to_ycbcr(r, g, b);
+        return (cb, cr);
+    }
 
-        let target_color: u8;
-        let mut target_color_variance: u32 = 0;
+    /// Foreground coverage for a pixel in `[0.0, 1.0]`: `0.0` is fully the key
+    /// colour (show the plate), `1.0` is fully the subject. The band between the
+    /// inner and outer radii maps linearly to a feathered edge.
+    fn key_alpha(&self, color: &Color, thresholds: &Thresholds) -> f32 {
+        let (key_cb, key_cr) = self.key_chroma();
+        let (_, cb, cr) = rgb_to_ycbcr(color.r as f32, color.g as f32, color.b as f32);
+        let distance = ((cb - key_cb).powi(2) + (cr - key_cr).powi(2)).sqrt();
+
+        return if distance <= thresholds.inner_radius {
+            0.0
+        } else if distance >= thresholds.outer_radius {
+            1.0
+        } else {
+            (distance - thresholds.inner_radius)
+                / (thresholds.outer_radius - thresholds.inner_radius)
+        };
+    }
 
-        match self {
-            FilterType::Red => {
-                target_color_variance += u32::from(color.g).abs_diff(cut_off_1);
-                target_color_variance += u32::from(color.b).abs_diff(cut_off_2);
-                target_color = color.r;
-            }
-            FilterType::Blue => {
-                target_color_variance += u32::from(color.g).abs_diff(cut_off_1);
-                target_color_variance += u32::from(color.r).abs_diff(cut_off_2);
-                target_color = color.b;
-            }
-            FilterType::Green => {
-                target_color_variance += u32::from(color.b).abs_diff(cut_off_1);
-                target_color_variance += u32::from(color.r).abs_diff(cut_off_2);
-                target_color = color.g;
-            }
+    /// Pull the key channel toward the average of the other two for pixels kept
+    /// as foreground, removing the coloured fringe a green/blue/red plate casts
+    /// on the subject. Background pixels are left untouched.
+    fn suppress_spill(&self, color: Color, alpha: f32, spill: f32) -> Color {
+        if alpha <= 0.0 || spill <= 0.0 {
+            return color;
+        }
+
+        let Color { r, g, b, a } = color;
+        let clamp = |key: u8, other_a: u8, other_b: u8| -> u8 {
+            let average = (f32::from(other_a) + f32::from(other_b)) / 2.0;
+            return if f32::from(key) > average {
+                (f32::from(key) - spill * (f32::from(key) - average)) as u8
+            } else {
+                key
+            };
         };
 
-        return cut_off_range.contains(&target_color) && target_color_variance < cut_off_variance;
+        return match self {
+            FilterType::Red => Color {
+                r: clamp(r, g, b),
+                g,
+                b,
+                a,
+            },
+            FilterType::Blue => Color {
+                r,
+                g,
+                b: clamp(b, r, g),
+                a,
+            },
+            FilterType::Green => Color {
+                r,
+                g: clamp(g, r, b),
+                b,
+                a,
+            },
+        };
     }
 }
 
+/// Convert an RGB triple to BT.601 luma/chroma. Only Cb/Cr are used for keying,
+/// but Y is returned for completeness.
+fn rgb_to_ycbcr(r: f32, g: f32, b: f32) -> (f32, f32, f32) {
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let cb = 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b;
+    let cr = 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b;
+    return (y, cb, cr);
+}
+
+/// Alpha-blend a foreground pixel over the background plate: `alpha` is the
+/// foreground coverage, so `0.0` yields the plate and `1.0` the subject.
+fn composite(foreground: &Color, background: &Color, alpha: f32) -> Color {
+    let mix = |fg: u8, bg: u8| -> u8 {
+        return (f32::from(fg) * alpha + f32::from(bg) * (1.0 - alpha)) as u8;
+    };
+
+    return Color {
+        r: mix(foreground.r, background.r),
+        g: mix(foreground.g, background.g),
+        b: mix(foreground.b, background.b),
+        a: 255,
+    };
+}
+
 #[cfg(test)]
 mod test {
-    use crate::{BufferToColor, Color, ColorsToBuffer, FilterType};
+    use crate::{encode_delta, BufferToColor, Color, ColorsToBuffer, FilterType, Thresholds};
+
+    /// Reconstruct `new` from `old` plus an `encode_delta` body, mirroring what
+    /// a client does on the wire.
+    fn apply_delta(old: &[u8], body: &[u8]) -> Vec<u8> {
+        let new_len = u32::from_be_bytes(body[0..4].try_into().unwrap()) as usize;
+        let mut out = old.to_vec();
+        out.resize(new_len, 0);
+
+        let mut i = 4;
+        while i < body.len() {
+            let offset = u32::from_be_bytes(body[i..i + 4].try_into().unwrap()) as usize;
+            let run_len = u32::from_be_bytes(body[i + 4..i + 8].try_into().unwrap()) as usize;
+            i += 8;
+            out[offset..offset + run_len].copy_from_slice(&body[i..i + run_len]);
+            i += run_len;
+        }
+
+        return out;
+    }
 
     #[test]
     fn test_process_frame_buffer_len() {
@@ -242,23 +861,58 @@ mod test {
     }
 
     #[test]
-    fn test_filter() {
-        let target1 = Color {
-            r: 235,
-            g: 20,
-            b: 10,
-            a: 5,
+    fn test_key_alpha() {
+        let thresholds = Thresholds::default();
+        let filter = FilterType::Red;
+
+        // Pure key colour sits at the reference chroma, so it is fully keyed out.
+        let keyed = Color {
+            r: 255,
+            g: 0,
+            b: 0,
+            a: 255,
         };
+        assert_eq!(filter.key_alpha(&keyed, &thresholds), 0.0);
+
+        // A neutral pixel is far from red in the chroma plane and stays foreground.
+        let subject = Color {
+            r: 128,
+            g: 128,
+            b: 128,
+            a: 255,
+        };
+        assert_eq!(filter.key_alpha(&subject, &thresholds), 1.0);
+    }
 
-        let target2 = Color {
+    #[test]
+    fn test_spill_suppression() {
+        let filter = FilterType::Green;
+        // A foreground pixel with a green cast has its green pulled to the r/b average.
+        let spilled = Color {
             r: 100,
-            g: 50,
-            b: 10,
-            a: 5,
+            g: 200,
+            b: 100,
+            a: 255,
         };
+        let cleaned = filter.suppress_spill(spilled, 1.0, 1.0);
+        assert_eq!(cleaned.g, 100);
+    }
 
-        let filter = FilterType::Red;
-        assert_eq!(filter.should_cut_off(&target1), true);
-        assert_eq!(filter.should_cut_off(&target2), false);
+    #[test]
+    fn test_delta_round_trip() {
+        let old: Vec<u8> = vec![0, 1, 2, 3, 4, 5, 6, 7];
+        let new: Vec<u8> = vec![0, 1, 9, 9, 4, 5, 6, 8];
+
+        let body = encode_delta(&old, &new);
+        assert_eq!(apply_delta(&old, &body), new);
+    }
+
+    #[test]
+    fn test_delta_length_change() {
+        let old: Vec<u8> = vec![1, 2, 3, 4];
+        let new: Vec<u8> = vec![1, 2, 3, 4, 5, 6];
+
+        let body = encode_delta(&old, &new);
+        assert_eq!(apply_delta(&old, &body), new);
     }
 }